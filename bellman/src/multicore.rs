@@ -1,25 +1,60 @@
 //! An interface for dealing with the kinds of parallel computations involved in
-//! `bellman`. It's currently just a thin wrapper around [`CpuPool`] and
-//! [`crossbeam`] but may be extended in the future to allow for various
-//! parallelism strategies.
+//! `bellman`. With the `multicore` feature (the default) it is a thin wrapper
+//! around a shared [`rayon`] thread pool; without it, every operation runs
+//! inline on the calling thread so the crate builds on targets with no thread
+//! support, such as `wasm32-wasi` and `wasm32-unknown-unknown`.
 //!
-//! [`CpuPool`]: futures_cpupool::CpuPool
+//! The two implementations expose an identical public surface (`Worker`,
+//! `Waiter`, `compute`, `scope`, `log_num_cpus`, ...), so downstream code such
+//! as the prover and multiexp compiles unchanged against either. The
+//! single-threaded path pulls in none of the `futures`/`crossbeam`/`rayon`
+//! stack; exercise it with `cargo build --no-default-features`.
 
 #[cfg(feature = "multicore")]
 pub mod implementation {
-    use crossbeam::{self, thread::Scope};
-    use futures::{Future, IntoFuture, Poll};
-    use futures_cpupool::{CpuFuture, CpuPool};
+    use crossbeam_channel::{bounded, Receiver, TryRecvError};
+    use lazy_static::lazy_static;
+    use log::warn;
     use num_cpus;
+    use rayon::{Scope, ThreadPool, ThreadPoolBuilder, Yield};
+    use std::env;
+    use std::panic::{self, AssertUnwindSafe};
     use std::sync::atomic::{AtomicUsize, Ordering, AtomicBool};
+    use std::thread;
 
     pub static NUM_CPUS: AtomicUsize = AtomicUsize::new(12);
     pub static HAS_LOADED: AtomicBool = AtomicBool::new(false);
 
+    lazy_static! {
+        /// A single process-wide, work-stealing thread pool shared by every
+        /// `Worker`. Sizing it once to `NUM_CPUS` keeps total live threads
+        /// bounded, and work-stealing lets a task that blocks on a nested
+        /// result yield the thread to other ready tasks.
+        static ref POOL: ThreadPool = {
+            // `Worker::new` resolves `NUM_CPUS` before any `scope` runs, so by
+            // the time this is forced the count reflects `BELLMAN_NUM_CPUS` or
+            // `num_cpus::get()`.
+            ThreadPoolBuilder::new()
+                .num_threads(NUM_CPUS.load(Ordering::SeqCst))
+                .build()
+                .expect("failed to build the bellman thread pool")
+        };
+    }
+
+    /// How aggressively a `Worker` should consume CPU resources.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Priority {
+        /// Use the full thread pool. This is what [`Worker::new`] selects.
+        High,
+        /// Run at reduced parallelism, leaving cores free for latency-sensitive
+        /// work. A wallet can generate proofs at `Low` priority in the
+        /// background without saturating every core and stalling its UI.
+        Low,
+    }
+
     #[derive(Clone)]
     pub struct Worker {
         cpus: usize,
-        pool: CpuPool,
     }
 
     impl Worker {
@@ -27,41 +62,75 @@ pub mod implementation {
         // all `Worker` instances have the same number of
         // CPUs configured.
         pub(crate) fn new_with_cpus(cpus: usize) -> Worker {
-            Worker {
-                cpus,
-                pool: CpuPool::new(cpus),
-            }
+            Worker { cpus }
         }
 
         pub fn new() -> Worker {
+            Self::new_with_priority(Priority::High)
+        }
+
+        /// Construct a `Worker` whose effective parallelism is scaled for the
+        /// given [`Priority`]. `Low` pins the effective CPU count to half of
+        /// `NUM_CPUS` (at least one), so chunking is coarser and fewer tasks
+        /// run at once; `High` uses the full count. Because `log_num_cpus`
+        /// reads the effective count, the radix/FFT splitting downstream stays
+        /// consistent with the reduced parallelism.
+        pub fn new_with_priority(priority: Priority) -> Worker {
             if !HAS_LOADED.load(Ordering::SeqCst) {
-                NUM_CPUS.store(num_cpus::get(), Ordering::SeqCst);
+                // Let operators cap parallelism without recompiling (e.g. when
+                // `bellman` shares a machine with other parallel workloads, or
+                // runs in a constrained container). Fall back to the detected
+                // CPU count when `BELLMAN_NUM_CPUS` is unset or unparseable, and
+                // clamp to at least one so a `0` override (a plausible "no extra
+                // threads" misconfiguration) can't divide-by-zero in `scope` or
+                // trip `log2_floor`'s `num > 0` assertion.
+                let cpus = env::var("BELLMAN_NUM_CPUS")
+                    .ok()
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .unwrap_or_else(num_cpus::get)
+                    .max(1);
+                NUM_CPUS.store(cpus, Ordering::SeqCst);
                 HAS_LOADED.store(true, Ordering::SeqCst)
             }
 
-            Self::new_with_cpus(NUM_CPUS.load(Ordering::SeqCst))
+            let cpus = NUM_CPUS.load(Ordering::SeqCst);
+            let cpus = match priority {
+                Priority::High => cpus,
+                Priority::Low => (cpus / 2).max(1),
+            };
+
+            Self::new_with_cpus(cpus)
         }
 
         pub fn log_num_cpus(&self) -> u32 {
             log2_floor(self.cpus)
         }
 
-        pub fn compute<F, R>(&self, f: F) -> WorkerFuture<R::Item, R::Error>
+        pub fn compute<F, R>(&self, f: F) -> Waiter<R>
         where
             F: FnOnce() -> R + Send + 'static,
-            R: IntoFuture + 'static,
-            R::Future: Send + 'static,
-            R::Item: Send + 'static,
-            R::Error: Send + 'static,
+            R: Send + 'static,
         {
-            WorkerFuture {
-                future: self.pool.spawn_fn(f),
-            }
+            let (sender, receiver) = bounded(1);
+
+            POOL.spawn(move || {
+                // Catch a panic in `f` and ship the payload to the `Waiter` so
+                // it can be re-raised with the original message and backtrace,
+                // rather than dropping `sender` mid-unwind and leaving `wait`
+                // to report an opaque "sender dropped" failure. A send error
+                // only happens if the `Waiter` was dropped before the result
+                // was produced, in which case nobody wants it.
+                let result = panic::catch_unwind(AssertUnwindSafe(f));
+                let _ = sender.send(result);
+            });
+
+            Waiter { receiver }
         }
 
         pub fn scope<'a, F, R>(&self, elements: usize, f: F) -> R
         where
-            F: FnOnce(&Scope<'a>, usize) -> R,
+            F: FnOnce(&Scope<'a>, usize) -> R + Send,
+            R: Send,
         {
             let chunk_size = if elements < self.cpus {
                 1
@@ -69,22 +138,83 @@ pub mod implementation {
                 elements / self.cpus
             };
 
-            // TODO: Handle case where threads fail
-            crossbeam::scope(|scope| f(scope, chunk_size))
-                .expect("Threads aren't allowed to fail yet")
+            // Run everything on the single shared pool. Rayon's work-stealing
+            // means nested `scope`/`join` calls on the same pool can't deadlock
+            // or explode the live-thread count: a task that blocks waiting on a
+            // nested result yields its thread to other ready tasks. Dispatching
+            // onto a second, uncoordinated pool would reintroduce exactly the
+            // thread-explosion the redesign set out to remove, so there is no
+            // cap or inline-overflow branch to manage.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                POOL.scope(|scope| f(scope, chunk_size))
+            }));
+
+            // Surface the genuine panic (failed witness assignment, an FFT or
+            // multiexp assertion, ...) with its original message and backtrace,
+            // rather than masking every failure behind one opaque string.
+            match result {
+                Ok(value) => value,
+                Err(payload) => panic::resume_unwind(payload),
+            }
         }
     }
 
-    pub struct WorkerFuture<T, E> {
-        future: CpuFuture<T, E>,
+    /// A handle to the result of a [`Worker::compute`] call.
+    ///
+    /// Every consumer of `compute` ultimately blocks on the result, so rather
+    /// than threading `futures` through the whole prover we hand back a plain
+    /// channel receiver and let the caller block on it with [`Waiter::wait`].
+    pub struct Waiter<T> {
+        receiver: Receiver<thread::Result<T>>,
     }
 
-    impl<T: Send + 'static, E: Send + 'static> Future for WorkerFuture<T, E> {
-        type Item = T;
-        type Error = E;
+    impl<T> Waiter<T> {
+        /// Wait for the computation to finish and return its result.
+        ///
+        /// If the computation panicked, that panic is resumed here so the
+        /// caller sees the original message and backtrace.
+        pub fn wait(self) -> T {
+            let result = if rayon::current_thread_index().is_some() {
+                // Blocking outright from a pool worker thread can deadlock: the
+                // sub-task we're waiting on may need a worker to run, and a raw
+                // `recv()` holds this one hostage without yielding. Callers
+                // should prefer `Worker::scope` here (hence the warning), but we
+                // still cooperate with the pool — running other pending tasks
+                // while we wait — so a nested `compute().wait()` makes progress
+                // instead of starving the pool.
+                warn!(
+                    "Waiter::wait called from within the thread pool; prefer \
+                     Worker::scope to avoid starving the pool"
+                );
 
-        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-            self.future.poll()
+                loop {
+                    match self.receiver.try_recv() {
+                        Ok(result) => break result,
+                        Err(TryRecvError::Disconnected) => {
+                            panic!("sender dropped without producing a result")
+                        }
+                        // Nothing yet: run a queued pool task if there is one,
+                        // otherwise another worker is already on it, so block.
+                        Err(TryRecvError::Empty) => match rayon::yield_now() {
+                            Some(Yield::Executed) => continue,
+                            _ => {
+                                break self.receiver.recv().expect(
+                                    "sender dropped without producing a result",
+                                )
+                            }
+                        },
+                    }
+                }
+            } else {
+                self.receiver
+                    .recv()
+                    .expect("sender dropped without producing a result")
+            };
+
+            match result {
+                Ok(value) => value,
+                Err(payload) => panic::resume_unwind(payload),
+            }
         }
     }
 
@@ -111,11 +241,34 @@ pub mod implementation {
         assert_eq!(log2_floor(7), 2);
         assert_eq!(log2_floor(8), 3);
     }
+
+    #[test]
+    fn test_scope_propagates_panic() {
+        let worker = Worker::new();
+
+        let panic = panic::catch_unwind(AssertUnwindSafe(|| {
+            worker.scope(1, |_scope, _chunk| panic!("boom from inside scope"))
+        }))
+        .expect_err("scope should propagate the child panic");
+
+        let message = panic
+            .downcast_ref::<&'static str>()
+            .copied()
+            .expect("original panic payload should be preserved");
+        assert_eq!(message, "boom from inside scope");
+    }
 }
 
 #[cfg(not(feature = "multicore"))]
 mod implementation {
-    use futures::{future, Future, IntoFuture, Poll};
+    /// How aggressively a `Worker` should consume CPU resources. On the
+    /// single-threaded build both variants behave identically, but the type is
+    /// kept so callers compile unchanged across both `cfg` arms.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Priority {
+        High,
+        Low,
+    }
 
     #[derive(Clone)]
     pub struct Worker;
@@ -125,19 +278,20 @@ mod implementation {
             Worker
         }
 
+        pub fn new_with_priority(_priority: Priority) -> Worker {
+            Worker
+        }
+
         pub fn log_num_cpus(&self) -> u32 {
             0
         }
 
-        pub fn compute<F, R>(&self, f: F) -> R::Future
+        pub fn compute<F, R>(&self, f: F) -> Waiter<R>
         where
             F: FnOnce() -> R + Send + 'static,
-            R: IntoFuture + 'static,
-            R::Future: Send + 'static,
-            R::Item: Send + 'static,
-            R::Error: Send + 'static,
+            R: Send + 'static,
         {
-            f().into_future()
+            Waiter::done(f())
         }
 
         pub fn scope<F, R>(&self, elements: usize, f: F) -> R
@@ -148,16 +302,23 @@ mod implementation {
         }
     }
 
-    pub struct WorkerFuture<T, E> {
-        future: future::FutureResult<T, E>,
+    /// A handle to the result of a [`Worker::compute`] call.
+    ///
+    /// On the single-threaded build the work has already run by the time the
+    /// `Waiter` exists, so it just holds the value.
+    pub struct Waiter<T> {
+        value: Option<T>,
     }
 
-    impl<T: Send + 'static, E: Send + 'static> Future for WorkerFuture<T, E> {
-        type Item = T;
-        type Error = E;
+    impl<T> Waiter<T> {
+        /// Return the already-computed result.
+        pub fn wait(mut self) -> T {
+            self.value.take().expect("result already taken")
+        }
 
-        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-            self.future.poll()
+        /// Construct an already-ready `Waiter` holding `value`.
+        pub(crate) fn done(value: T) -> Waiter<T> {
+            Waiter { value: Some(value) }
         }
     }
 